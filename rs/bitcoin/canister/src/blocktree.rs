@@ -0,0 +1,422 @@
+//! The tree of not-yet-stable Bitcoin blocks, rooted at the most recent
+//! stable block, and the header validation applied before a block is
+//! allowed to extend it.
+//!
+//! The adapter is not a trusted component: it is free to return any bytes it
+//! likes as a "block". Before a block is linked into the tree we therefore
+//! check that its header carries enough proof-of-work for the difficulty
+//! target it claims, and that the claimed target itself follows from the
+//! chain's retargeting rule.
+
+use bitcoin::{
+    blockdata::block::Block, hash_types::BlockHash, util::uint::Uint256, BlockHeader, Network,
+};
+
+/// Number of blocks between mainnet/testnet difficulty retargets.
+const DIFFICULTY_ADJUSTMENT_INTERVAL: u32 = 2016;
+
+/// Target timespan (2 weeks) that `DIFFICULTY_ADJUSTMENT_INTERVAL` blocks are
+/// supposed to cover, in seconds.
+const TARGET_TIMESPAN: i64 = DIFFICULTY_ADJUSTMENT_INTERVAL as i64 * 600;
+
+/// On testnet-3, a block whose timestamp is more than this many seconds
+/// after its parent's may be mined at the minimum difficulty.
+const TESTNET_MAX_BLOCK_SPACING_SECS: u32 = 20 * 60;
+
+/// A block together with the blocks that directly extend it.
+pub struct BlockTree {
+    pub root: Block,
+    pub children: Vec<BlockTree>,
+}
+
+impl BlockTree {
+    /// Creates a new tree rooted at the given (already-validated) block.
+    pub fn new(root: Block) -> Self {
+        Self {
+            root,
+            children: vec![],
+        }
+    }
+
+    /// Returns the tip with the most accumulated work below this node,
+    /// along with the depth of the chain leading to it and the work
+    /// accumulated along that chain (not counting this node's own block).
+    pub fn tip(&self) -> (&Block, u32, Uint256) {
+        self.children
+            .iter()
+            .map(|child| {
+                let (tip, depth, work) = child.tip();
+                (tip, depth + 1, work + block_work(child.root.header.bits))
+            })
+            .max_by_key(|(_, _, work)| *work)
+            .unwrap_or((&self.root, 0, Uint256::from_u64(0).unwrap()))
+    }
+
+    /// Finds the tree node containing the given block hash, along with the
+    /// chain of headers from the tree's root down to (and including) it.
+    pub fn find_mut(&mut self, block_hash: &BlockHash) -> Option<(&mut BlockTree, Vec<&Block>)> {
+        if &self.root.block_hash() == block_hash {
+            return Some((self, vec![&self.root]));
+        }
+        for child in self.children.iter_mut() {
+            if let Some((node, mut chain)) = child.find_mut(block_hash) {
+                chain.insert(0, &self.root);
+                return Some((node, chain));
+            }
+        }
+        None
+    }
+
+    /// Returns every block in this tree, in depth-first order.
+    pub fn blocks(&self) -> Vec<&Block> {
+        let mut blocks = vec![&self.root];
+        for child in &self.children {
+            blocks.extend(child.blocks());
+        }
+        blocks
+    }
+
+    /// Finds the block with the given hash, if it's still in the tree.
+    pub fn find(&self, block_hash: &BlockHash) -> Option<&Block> {
+        if &self.root.block_hash() == block_hash {
+            return Some(&self.root);
+        }
+        self.children.iter().find_map(|child| child.find(block_hash))
+    }
+
+    /// Returns the blocks from the root down to `tip()`, i.e. the chain with
+    /// the most accumulated work. Any block not in this list is on a losing
+    /// fork: it may still be in the tree (not yet pruned), but can never be
+    /// confirmed.
+    pub fn main_chain(&self) -> Vec<&Block> {
+        let mut chain = vec![&self.root];
+        let mut node = self;
+        while let Some(child) = node
+            .children
+            .iter()
+            .max_by_key(|child| child.tip().2 + block_work(child.root.header.bits))
+        {
+            chain.push(&child.root);
+            node = child;
+        }
+        chain
+    }
+}
+
+/// An error returned when a block could not be inserted into a [`BlockTree`].
+#[derive(Debug, PartialEq, Eq)]
+pub enum BlockInsertionError {
+    /// The block does not extend any block already in the tree.
+    DoesNotExtendTree(BlockHash),
+    /// The block's header failed proof-of-work or difficulty-retarget
+    /// validation.
+    InvalidHeader(BlockHash, HeaderValidationError),
+}
+
+/// Why a header was rejected.
+#[derive(Debug, PartialEq, Eq)]
+pub enum HeaderValidationError {
+    /// The block's hash, interpreted as a 256-bit number, exceeds the target
+    /// implied by its `bits` field.
+    InsufficientWork,
+    /// The `bits` field does not match what the retargeting rule dictates.
+    TargetMismatch { expected_bits: u32, actual_bits: u32 },
+}
+
+/// Decodes the compact "nBits" representation of a difficulty target into
+/// its full 256-bit form.
+///
+/// `bits` packs the target as a mantissa/exponent pair: the low 3 bytes are
+/// the mantissa and the 4th (most significant) byte is the exponent, so
+/// `target = mantissa << (8 * (exponent - 3))`. Bit 23 (`0x00800000`) is a
+/// sign bit, not part of the mantissa's magnitude; per Bitcoin Core's
+/// `SetCompact`, a `bits` value with that bit set (and a non-zero mantissa)
+/// encodes a negative target, which is never satisfiable by any real block
+/// hash, so it's decoded as a target of zero rather than folded into the
+/// magnitude as a larger positive value.
+pub fn compact_to_target(bits: u32) -> Uint256 {
+    let exponent = bits >> 24;
+    let mantissa = bits & 0x007f_ffff;
+    if mantissa != 0 && bits & 0x0080_0000 != 0 {
+        return Uint256::from_u64(0).unwrap();
+    }
+    let mantissa = Uint256::from_u64(mantissa as u64).unwrap();
+    if exponent <= 3 {
+        mantissa >> (8 * (3 - exponent)) as usize
+    } else {
+        mantissa << (8 * (exponent - 3)) as usize
+    }
+}
+
+/// The proof-of-work a block with the given `bits` represents, per Bitcoin
+/// Core's `GetBlockProof`: `(~target / (target + 1)) + 1`, i.e. (loosely)
+/// the expected number of hashes needed to find a block at that target.
+/// Used to compare forks by accumulated work rather than by block count,
+/// since a harder, shorter fork can outweigh an easier, longer one.
+pub(crate) fn block_work(bits: u32) -> Uint256 {
+    let target = compact_to_target(bits);
+    let max = Uint256::from_be_bytes([0xff; 32]);
+    (max - target) / (target + Uint256::from_u64(1).unwrap()) + Uint256::from_u64(1).unwrap()
+}
+
+/// Returns the block hash interpreted as a 256-bit number, in the same byte
+/// order as [`compact_to_target`] (i.e. the natural order used when
+/// comparing a hash against a target).
+fn block_hash_to_uint256(hash: &BlockHash) -> Uint256 {
+    let mut bytes = *hash.as_hash().as_inner();
+    bytes.reverse();
+    Uint256::from_be_bytes(bytes)
+}
+
+/// Validates a block's proof-of-work against the target its `bits` field
+/// claims.
+fn validate_pow(header: &BlockHeader) -> Result<(), HeaderValidationError> {
+    let target = compact_to_target(header.bits);
+    let hash = block_hash_to_uint256(&header.block_hash());
+    if hash > target {
+        return Err(HeaderValidationError::InsufficientWork);
+    }
+    Ok(())
+}
+
+/// Computes the `bits` a block at `height` is required to use, given its
+/// parent's header and, if `height` is a retarget height, the header at
+/// height `height - DIFFICULTY_ADJUSTMENT_INTERVAL` (the first block of the
+/// period that `parent` ends).
+///
+/// If `height` is a retarget height but `retarget_start` is `None` (the
+/// canister hasn't retained a header that far back, e.g. it's validating
+/// its first retarget since the state was bootstrapped), the claimed `bits`
+/// is trusted rather than checked against the retarget rule: proof-of-work
+/// is still validated unconditionally by `validate_header`, so this only
+/// widens the set of valid `bits` values rather than skipping validation
+/// altogether.
+fn expected_bits(
+    network: Network,
+    height: u32,
+    parent: &BlockHeader,
+    retarget_start: Option<&BlockHeader>,
+    header: &BlockHeader,
+) -> u32 {
+    if height % DIFFICULTY_ADJUSTMENT_INTERVAL != 0 {
+        if network == Network::Testnet
+            && header.time > parent.time + TESTNET_MAX_BLOCK_SPACING_SECS
+        {
+            return max_target_bits(network);
+        }
+        return parent.bits;
+    }
+
+    let retarget_start = match retarget_start {
+        Some(retarget_start) => retarget_start,
+        None => return header.bits,
+    };
+    let actual_timespan = (parent.time as i64 - retarget_start.time as i64)
+        .clamp(TARGET_TIMESPAN / 4, TARGET_TIMESPAN * 4);
+
+    let old_target = compact_to_target(parent.bits);
+    let max_target = compact_to_target(max_target_bits(network));
+    let new_target = std::cmp::min(
+        mul_div_target(old_target, actual_timespan as u64, TARGET_TIMESPAN as u64),
+        max_target,
+    );
+    target_to_compact(new_target)
+}
+
+/// The `bits` encoding of the network's minimum-difficulty (maximum) target.
+fn max_target_bits(network: Network) -> u32 {
+    match network {
+        Network::Bitcoin | Network::Testnet => 0x1d00ffff,
+        Network::Signet => 0x1e0377ae,
+        Network::Regtest => 0x207fffff,
+    }
+}
+
+/// `target * numerator / denominator`, computed in 256-bit arithmetic.
+fn mul_div_target(target: Uint256, numerator: u64, denominator: u64) -> Uint256 {
+    (target * Uint256::from_u64(numerator).unwrap()) / Uint256::from_u64(denominator).unwrap()
+}
+
+/// Re-encodes a 256-bit target into its compact "nBits" form.
+fn target_to_compact(mut target: Uint256) -> u32 {
+    let mut size = (target.bits() + 7) / 8;
+    let mantissa = if size <= 3 {
+        target = target << (8 * (3 - size)) as usize;
+        target.low_u64() as u32
+    } else {
+        target = target >> (8 * (size - 3)) as usize;
+        target.low_u64() as u32
+    };
+    // If the sign bit (0x00800000) would be set, shift in an extra byte, so
+    // the result isn't decoded as a negative target by `compact_to_target`.
+    let (mantissa, size) = if mantissa & 0x0080_0000 != 0 {
+        (mantissa >> 8, size + 1)
+    } else {
+        (mantissa, size)
+    };
+    (size << 24) | mantissa
+}
+
+/// Validates `header`, which extends `parent` at `height`, against the
+/// proof-of-work and difficulty-retarget rules of `network`.
+///
+/// When `height` is a multiple of `DIFFICULTY_ADJUSTMENT_INTERVAL`,
+/// `retarget_start` should be the header at height
+/// `height - DIFFICULTY_ADJUSTMENT_INTERVAL`, if the canister has retained
+/// it; see
+/// `expected_bits` for how a missing `retarget_start` is handled.
+pub fn validate_header(
+    network: Network,
+    height: u32,
+    parent: &BlockHeader,
+    retarget_start: Option<&BlockHeader>,
+    header: &BlockHeader,
+) -> Result<(), HeaderValidationError> {
+    let expected = expected_bits(network, height, parent, retarget_start, header);
+    if header.bits != expected {
+        return Err(HeaderValidationError::TargetMismatch {
+            expected_bits: expected,
+            actual_bits: header.bits,
+        });
+    }
+    validate_pow(header)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block_with(prev_blockhash: BlockHash, nonce: u32) -> Block {
+        block_with_bits(prev_blockhash, nonce, 0x207fffff)
+    }
+
+    fn block_with_bits(prev_blockhash: BlockHash, nonce: u32, bits: u32) -> Block {
+        Block {
+            header: BlockHeader {
+                version: 1,
+                prev_blockhash,
+                merkle_root: Default::default(),
+                time: 0,
+                bits,
+                nonce,
+            },
+            txdata: vec![],
+        }
+    }
+
+    #[test]
+    fn main_chain_follows_the_tip_with_the_most_accumulated_work() {
+        let root = block_with(BlockHash::default(), 0);
+        let root_hash = root.block_hash();
+        let mut tree = BlockTree::new(root);
+
+        let short_fork = block_with(root_hash, 1);
+        let long_fork = block_with(root_hash, 2);
+        let long_fork_hash = long_fork.block_hash();
+
+        tree.children.push(BlockTree::new(short_fork));
+        tree.children.push(BlockTree::new(long_fork));
+        let long_fork_child = block_with(long_fork_hash, 3);
+        tree.children[1].children.push(BlockTree::new(long_fork_child));
+
+        let main_chain: Vec<BlockHash> =
+            tree.main_chain().into_iter().map(|b| b.block_hash()).collect();
+        assert_eq!(main_chain, vec![root_hash, long_fork_hash, tree.children[1].children[0].root.block_hash()]);
+    }
+
+    #[test]
+    fn main_chain_prefers_a_harder_shorter_fork_over_an_easier_longer_one() {
+        let root = block_with(BlockHash::default(), 0);
+        let root_hash = root.block_hash();
+        let mut tree = BlockTree::new(root);
+
+        // A single block at mainnet genesis difficulty outweighs a much
+        // longer chain mined at the (far easier) regtest minimum.
+        let hard_fork = block_with_bits(root_hash, 1, 0x1d00ffff);
+        let hard_fork_hash = hard_fork.block_hash();
+        tree.children.push(BlockTree::new(hard_fork));
+
+        let mut easy_fork = BlockTree::new(block_with(root_hash, 2));
+        let mut node = &mut easy_fork;
+        for i in 0..20u32 {
+            node.children.push(BlockTree::new(block_with(node.root.block_hash(), 3 + i)));
+            node = &mut node.children[0];
+        }
+        tree.children.push(easy_fork);
+
+        let main_chain: Vec<BlockHash> =
+            tree.main_chain().into_iter().map(|b| b.block_hash()).collect();
+        assert_eq!(main_chain, vec![root_hash, hard_fork_hash]);
+    }
+
+    #[test]
+    fn compact_to_target_decodes_genesis_difficulty() {
+        // Bitcoin mainnet genesis block's `bits`.
+        let target = compact_to_target(0x1d00ffff);
+        assert_eq!(target, Uint256::from_u64(0x00ffff).unwrap() << (8 * 26));
+    }
+
+    #[test]
+    fn compact_to_target_rejects_a_negative_encoding() {
+        // Bit 23 set with a non-zero mantissa is Core's "negative target"
+        // encoding; it must not be folded into the magnitude as a larger
+        // positive target.
+        assert_eq!(compact_to_target(0x01800001), Uint256::from_u64(0).unwrap());
+    }
+
+    #[test]
+    fn target_to_compact_round_trips() {
+        let target = compact_to_target(0x1d00ffff);
+        assert_eq!(target_to_compact(target), 0x1d00ffff);
+    }
+
+    #[test]
+    fn retarget_height_trusts_claimed_bits_without_a_retarget_start() {
+        // An easy target (regtest's minimum difficulty), which roughly half
+        // of all hashes satisfy, this header's included.
+        let easy_bits = 0x207fffff;
+        let parent = BlockHeader {
+            version: 1,
+            prev_blockhash: BlockHash::default(),
+            merkle_root: Default::default(),
+            time: 1_000_000,
+            bits: easy_bits,
+            nonce: 0,
+        };
+        let mut header = parent;
+        header.time += 600;
+        header.bits = easy_bits;
+        assert_eq!(
+            validate_header(
+                Network::Bitcoin,
+                DIFFICULTY_ADJUSTMENT_INTERVAL,
+                &parent,
+                None,
+                &header,
+            ),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn non_retarget_height_must_match_parent_bits() {
+        let parent = BlockHeader {
+            version: 1,
+            prev_blockhash: BlockHash::default(),
+            merkle_root: Default::default(),
+            time: 1_000_000,
+            bits: 0x1d00ffff,
+            nonce: 0,
+        };
+        let mut header = parent;
+        header.time += 600;
+        header.bits = 0x1d00fffe;
+        assert_eq!(
+            validate_header(Network::Bitcoin, 1, &parent, None, &header),
+            Err(HeaderValidationError::TargetMismatch {
+                expected_bits: 0x1d00ffff,
+                actual_bits: 0x1d00fffe,
+            })
+        );
+    }
+}
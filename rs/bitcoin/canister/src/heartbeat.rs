@@ -1,17 +1,26 @@
-use crate::{blocktree::BlockDoesNotExtendTree, state::State, store, BitcoinCanister};
+use crate::{
+    blocktree::BlockInsertionError,
+    state::{State, SyncMode},
+    store, BitcoinCanister,
+};
 use bitcoin::{
     hash_types::{BlockHash, TxMerkleNode},
     hashes::Hash,
 };
 use ic_btc_types_internal::{
     BitcoinAdapterRequestWrapper, BitcoinAdapterResponseWrapper, Block, GetSuccessorsRequest,
-    Transaction,
+    SendTransactionRequest, Transaction,
 };
 use ic_logger::{debug, error, info, ReplicaLogger};
 use ic_registry_subnet_features::BitcoinFeature;
 use ic_replicated_state::bitcoin_state::{
     BitcoinState as ReplicatedBitcoinState, BitcoinStateError,
 };
+use std::collections::{HashMap, HashSet};
+
+/// Number of heartbeats to wait for a submitted transaction to appear in a
+/// block before re-pushing a `SendTransactionRequest` to the adapter.
+const RETRANSMISSION_INTERVAL_HEARTBEATS: u32 = 10;
 
 impl BitcoinCanister {
     /// The heartbeat of the Bitcoin canister.
@@ -57,6 +66,8 @@ impl BitcoinCanister {
                         | Err(BitcoinStateError::NonMatchingResponse { .. }) => unreachable!(),
                     }
                 }
+
+                retransmit_outbound_transactions(&mut state, &self.log, &self.metrics, &network_label);
             }
             BitcoinFeature::Paused | BitcoinFeature::Disabled => {
                 // Don't send requests to the adapter.
@@ -67,7 +78,119 @@ impl BitcoinCanister {
     }
 }
 
+/// Why a `SendTransactionRequest` could not be pushed.
+#[derive(Debug)]
+pub enum SendTransactionError {
+    /// `transaction` could not be decoded as a Bitcoin transaction.
+    MalformedTransaction,
+    /// The adapter queue rejected the request.
+    Queue(BitcoinStateError),
+}
+
+impl From<BitcoinStateError> for SendTransactionError {
+    fn from(err: BitcoinStateError) -> Self {
+        SendTransactionError::Queue(err)
+    }
+}
+
+/// Pushes a `SendTransactionRequest` for `transaction` to the adapter queue
+/// and starts tracking it so it gets retransmitted until it's mined.
+pub fn push_send_transaction_request(
+    state: &mut State,
+    transaction: Vec<u8>,
+) -> Result<(), SendTransactionError> {
+    let parsed: bitcoin::Transaction = bitcoin::consensus::deserialize(&transaction)
+        .map_err(|_| SendTransactionError::MalformedTransaction)?;
+    let txid = parsed.txid();
+    state
+        .adapter_queues
+        .push_request(BitcoinAdapterRequestWrapper::SendTransactionRequest(
+            SendTransactionRequest {
+                transaction: transaction.clone(),
+            },
+        ))?;
+    state.outbound_transactions.insert(txid, transaction);
+    Ok(())
+}
+
+// Retransmits submitted transactions that haven't appeared in a main-chain
+// block within `RETRANSMISSION_INTERVAL_HEARTBEATS`, and stops tracking
+// those that have reached the stability depth.
+fn retransmit_outbound_transactions(
+    state: &mut State,
+    log: &ReplicaLogger,
+    metrics: &crate::BitcoinCanisterMetrics,
+    network_label: &str,
+) {
+    let confirmed: Vec<bitcoin::Txid> = state
+        .outbound_transactions
+        .txids()
+        .into_iter()
+        .filter(|txid| {
+            store::get_transaction_status(state, txid)
+                .map_or(false, |status| status.confirmations >= store::STABILITY_THRESHOLD)
+        })
+        .collect();
+
+    for txid in confirmed {
+        state.outbound_transactions.remove(&txid);
+        metrics.observe_confirmed_transaction(network_label);
+        debug!(log, "Transaction {} confirmed; no longer tracking it", txid);
+    }
+
+    // Transactions already mined on the main chain are waiting for the
+    // stability depth, not for a retransmission.
+    let mined: HashSet<bitcoin::Txid> = state
+        .outbound_transactions
+        .txids()
+        .into_iter()
+        .filter(|txid| store::get_transaction_status(state, txid).is_some())
+        .collect();
+
+    let to_retransmit: Vec<(bitcoin::Txid, Vec<u8>)> = state
+        .outbound_transactions
+        .iter_mut()
+        .filter_map(|(txid, tx)| {
+            if mined.contains(txid) {
+                return None;
+            }
+            tx.heartbeats_since_last_push += 1;
+            if tx.heartbeats_since_last_push >= RETRANSMISSION_INTERVAL_HEARTBEATS {
+                tx.heartbeats_since_last_push = 0;
+                Some((*txid, tx.raw.clone()))
+            } else {
+                None
+            }
+        })
+        .collect();
+
+    for (txid, raw) in to_retransmit {
+        info!(log, "Retransmitting unconfirmed transaction {}", txid);
+        match state
+            .adapter_queues
+            .push_request(BitcoinAdapterRequestWrapper::SendTransactionRequest(
+                SendTransactionRequest { transaction: raw },
+            )) {
+            Ok(()) => {}
+            Err(err @ BitcoinStateError::QueueFull { .. }) => {
+                error!(
+                    log,
+                    "Could not retransmit transaction {} because the adapter queues are full. Error: {:?}",
+                    txid,
+                    err
+                );
+            }
+            Err(BitcoinStateError::TestnetFeatureNotEnabled)
+            | Err(BitcoinStateError::NonMatchingResponse { .. }) => unreachable!(),
+        }
+    }
+}
+
 // Retrieves a `GetSuccessorsRequest` to send to the adapter.
+//
+// In `SyncMode::Headers`, `headers_only` tells the adapter to answer with
+// header-only blocks plus a compact filter per block, except for the
+// hashes listed in `bodies_wanted`, for which it sends the full body.
 fn get_successors_request(state: &mut State) -> GetSuccessorsRequest {
     let mut processed_block_hashes: Vec<Vec<u8>> = store::get_unstable_blocks(state)
         .iter()
@@ -80,6 +203,12 @@ fn get_successors_request(state: &mut State) -> GetSuccessorsRequest {
     GetSuccessorsRequest {
         anchor,
         processed_block_hashes,
+        headers_only: matches!(state.sync_mode, SyncMode::Headers { .. }),
+        bodies_wanted: state
+            .pending_body_fetch
+            .iter()
+            .map(|hash| hash.to_vec())
+            .collect(),
     }
 }
 
@@ -101,20 +230,53 @@ fn process_adapter_responses(state: &mut State, log: &ReplicaLogger) -> u32 {
                 for block in r.blocks.into_iter() {
                     let btc_block = to_btc_block(&block);
                     let block_hash = btc_block.block_hash();
+                    let has_full_body = !btc_block.txdata.is_empty();
+                    // Once a full body has been received for a hash, stop
+                    // asking the adapter for it, regardless of whether it
+                    // could actually be inserted below: if insertion fails
+                    // because the block no longer extends the tree (e.g. a
+                    // reorg overtook it), re-requesting the same body every
+                    // heartbeat would never converge.
+                    if has_full_body {
+                        state.pending_body_fetch.remove(&block_hash);
+                    }
                     match store::insert_block(state, btc_block) {
                         Ok(()) => {}
-                        Err(BlockDoesNotExtendTree(_)) => {
+                        Err(BlockInsertionError::DoesNotExtendTree(_)) => {
                             error!(
                                 log,
                                 "Received block that doesn't extend existing blocks: {}",
                                 block_hash
                             );
                         }
+                        Err(BlockInsertionError::InvalidHeader(hash, err)) => {
+                            error!(
+                                log,
+                                "Rejected block {} with invalid header: {:?}", hash, err
+                            );
+                        }
                     }
                 }
+
+                // In header-first sync mode, a header-only block's compact
+                // filter (reported by the adapter alongside its header) is
+                // the only way the canister learns that block's filter (it
+                // can't compute one itself without the body), and is also
+                // enough to decide, without the body, whether the block is
+                // worth fetching in full.
+                record_header_filters(
+                    &mut state.filters,
+                    &mut state.pending_body_fetch,
+                    &state.sync_mode,
+                    &r.filters,
+                );
             }
             BitcoinAdapterResponseWrapper::SendTransactionResponse(_) => {
-                // TODO(EXC-911): Handle these responses too.
+                // Acknowledges that the adapter received the request; actual
+                // confirmation is tracked via the transaction index once the
+                // transaction appears in a block (see
+                // `retransmit_outbound_transactions`).
+                debug!(log, "Received SendTransactionResponse");
             }
         }
     }
@@ -122,6 +284,31 @@ fn process_adapter_responses(state: &mut State, log: &ReplicaLogger) -> u32 {
     store::main_chain_height(state)
 }
 
+/// Records the adapter-supplied filters of header-only blocks and queues a
+/// full-body fetch for any that match one of `sync_mode`'s watched scripts.
+/// A no-op in `SyncMode::FullBlocks`, where bodies are fetched unconditionally
+/// and `insert_block` computes each block's filter itself.
+fn record_header_filters(
+    filters: &mut HashMap<BlockHash, Vec<u8>>,
+    pending_body_fetch: &mut HashSet<BlockHash>,
+    sync_mode: &SyncMode,
+    received: &[(BlockHash, Vec<u8>)],
+) {
+    let watched_scripts = match sync_mode {
+        SyncMode::Headers { watched_scripts } => watched_scripts,
+        SyncMode::FullBlocks => return,
+    };
+    for (block_hash, filter) in received {
+        filters.insert(*block_hash, filter.clone());
+        if watched_scripts
+            .iter()
+            .any(|script| crate::filters::matches(filter, block_hash, script))
+        {
+            pending_body_fetch.insert(*block_hash);
+        }
+    }
+}
+
 fn to_btc_transaction(transaction: &Transaction) -> bitcoin::Transaction {
     bitcoin::Transaction {
         version: transaction.version,
@@ -202,4 +389,68 @@ mod tests {
         let state = bitcoin_canister.heartbeat(state, BitcoinFeature::Enabled);
         assert_eq!(state.adapter_queues.num_requests(), 1);
     }
+
+    #[test]
+    fn record_header_filters_persists_filters_and_flags_matches() {
+        let block_hash = BlockHash::default();
+        let script = vec![1u8; 20];
+        let filter = crate::filters::build_filter(&block_hash, &[script.clone()]);
+
+        let mut filters = HashMap::new();
+        let mut pending_body_fetch = HashSet::new();
+        let sync_mode = SyncMode::Headers {
+            watched_scripts: vec![script],
+        };
+
+        record_header_filters(
+            &mut filters,
+            &mut pending_body_fetch,
+            &sync_mode,
+            &[(block_hash, filter.clone())],
+        );
+
+        assert_eq!(filters.get(&block_hash), Some(&filter));
+        assert!(pending_body_fetch.contains(&block_hash));
+    }
+
+    #[test]
+    fn record_header_filters_persists_without_flagging_a_non_match() {
+        let block_hash = BlockHash::default();
+        let filter = crate::filters::build_filter(&block_hash, &[vec![1u8; 20]]);
+
+        let mut filters = HashMap::new();
+        let mut pending_body_fetch = HashSet::new();
+        let sync_mode = SyncMode::Headers {
+            watched_scripts: vec![vec![9u8; 20]],
+        };
+
+        record_header_filters(
+            &mut filters,
+            &mut pending_body_fetch,
+            &sync_mode,
+            &[(block_hash, filter.clone())],
+        );
+
+        assert_eq!(filters.get(&block_hash), Some(&filter));
+        assert!(pending_body_fetch.is_empty());
+    }
+
+    #[test]
+    fn record_header_filters_is_a_no_op_in_full_blocks_mode() {
+        let block_hash = BlockHash::default();
+        let filter = crate::filters::build_filter(&block_hash, &[vec![1u8; 20]]);
+
+        let mut filters = HashMap::new();
+        let mut pending_body_fetch = HashSet::new();
+
+        record_header_filters(
+            &mut filters,
+            &mut pending_body_fetch,
+            &SyncMode::FullBlocks,
+            &[(block_hash, filter)],
+        );
+
+        assert!(filters.is_empty());
+        assert!(pending_body_fetch.is_empty());
+    }
 }
@@ -0,0 +1,49 @@
+//! Tracks transactions the canister has handed to the adapter for
+//! broadcast, so they can be retransmitted until they're observed mined.
+
+use bitcoin::Txid;
+use std::collections::HashMap;
+
+/// A transaction that's been handed to the adapter for broadcast but not
+/// yet observed as confirmed.
+pub struct InFlightTransaction {
+    /// The raw transaction bytes to resend to the adapter.
+    pub raw: Vec<u8>,
+    /// Number of heartbeats since the request was last (re)pushed to the
+    /// adapter queue.
+    pub heartbeats_since_last_push: u32,
+}
+
+/// Transactions submitted via `SendTransactionRequest` that haven't yet been
+/// seen confirmed, keyed by txid.
+#[derive(Default)]
+pub struct OutboundTransactions {
+    in_flight: HashMap<Txid, InFlightTransaction>,
+}
+
+impl OutboundTransactions {
+    /// Starts tracking a newly-submitted transaction.
+    pub fn insert(&mut self, txid: Txid, raw: Vec<u8>) {
+        self.in_flight.insert(
+            txid,
+            InFlightTransaction {
+                raw,
+                heartbeats_since_last_push: 0,
+            },
+        );
+    }
+
+    /// Stops tracking a transaction, e.g. once it's confirmed.
+    pub fn remove(&mut self, txid: &Txid) -> Option<InFlightTransaction> {
+        self.in_flight.remove(txid)
+    }
+
+    /// Returns the txids currently being tracked.
+    pub fn txids(&self) -> Vec<Txid> {
+        self.in_flight.keys().copied().collect()
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (&Txid, &mut InFlightTransaction)> {
+        self.in_flight.iter_mut()
+    }
+}
@@ -0,0 +1,90 @@
+//! The in-memory view of the Bitcoin canister's state that the heartbeat
+//! logic operates on, converted from (and back into) the replicated state
+//! at the start and end of each heartbeat.
+
+use crate::blocktree::BlockTree;
+use crate::outbound::OutboundTransactions;
+use crate::tx_index::TxIndex;
+use bitcoin::{BlockHash, BlockHeader};
+use ic_replicated_state::bitcoin_state::{AdapterQueues, BitcoinState as ReplicatedBitcoinState, UtxoSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// How the canister keeps up with the chain tip.
+pub enum SyncMode {
+    /// Fetch and validate full blocks, as `insert_block` always has.
+    FullBlocks,
+    /// Fetch and validate headers only, and fetch a block's body lazily,
+    /// once its compact filter indicates it might be relevant to one of
+    /// `watched_scripts`.
+    Headers { watched_scripts: Vec<Vec<u8>> },
+}
+
+impl Default for SyncMode {
+    fn default() -> Self {
+        SyncMode::FullBlocks
+    }
+}
+
+/// Working state for a single heartbeat invocation.
+pub struct State {
+    pub utxos: UtxoSet,
+    pub adapter_queues: AdapterQueues,
+    pub unstable_blocks: BlockTree,
+    /// The height at which `unstable_blocks` is rooted.
+    pub unstable_blocks_anchor_height: u32,
+    /// Headers of retarget-boundary blocks (every 2016th height), keyed by
+    /// height, retained so that future difficulty retargets can be validated
+    /// without re-fetching the chain. Non-boundary headers aren't kept: they
+    /// are never looked up again once their block has been processed.
+    pub headers_by_height: BTreeMap<u32, BlockHeader>,
+    /// Index from transaction id to confirming block, kept in sync with
+    /// `unstable_blocks` as blocks are inserted, pruned, and stabilized.
+    pub tx_index: TxIndex,
+    /// Transactions submitted to the adapter for broadcast that haven't yet
+    /// been observed confirmed.
+    pub outbound_transactions: OutboundTransactions,
+    /// BIP158 compact filters, keyed by block hash, used to test script
+    /// membership without materializing the full UTXO set.
+    pub filters: HashMap<BlockHash, Vec<u8>>,
+    /// Whether to fetch full blocks or just headers, and (in the latter
+    /// case) which scripts to watch for.
+    pub sync_mode: SyncMode,
+    /// Header-only blocks whose body still needs to be fetched because
+    /// their filter indicated a match against `sync_mode`'s watched
+    /// scripts.
+    pub pending_body_fetch: HashSet<BlockHash>,
+}
+
+impl From<ReplicatedBitcoinState> for State {
+    fn from(state: ReplicatedBitcoinState) -> Self {
+        Self {
+            utxos: state.utxos,
+            adapter_queues: state.adapter_queues,
+            unstable_blocks: state.unstable_blocks,
+            unstable_blocks_anchor_height: state.unstable_blocks_anchor_height,
+            headers_by_height: state.headers_by_height,
+            tx_index: state.tx_index,
+            outbound_transactions: state.outbound_transactions,
+            filters: state.filters,
+            sync_mode: state.sync_mode,
+            pending_body_fetch: state.pending_body_fetch,
+        }
+    }
+}
+
+impl From<State> for ReplicatedBitcoinState {
+    fn from(state: State) -> Self {
+        Self {
+            utxos: state.utxos,
+            adapter_queues: state.adapter_queues,
+            unstable_blocks: state.unstable_blocks,
+            unstable_blocks_anchor_height: state.unstable_blocks_anchor_height,
+            headers_by_height: state.headers_by_height,
+            tx_index: state.tx_index,
+            outbound_transactions: state.outbound_transactions,
+            filters: state.filters,
+            sync_mode: state.sync_mode,
+            pending_body_fetch: state.pending_body_fetch,
+        }
+    }
+}
@@ -0,0 +1,396 @@
+//! Operations on the store of unstable blocks: inserting newly-received
+//! blocks (after validating their headers) and querying the resulting tree.
+
+use crate::blocktree::{block_work, validate_header, BlockInsertionError, BlockTree};
+use crate::filters;
+use crate::state::State;
+use crate::tx_index::TxLocation;
+use bitcoin::{hash_types::TxMerkleNode, hashes::Hash, Block, BlockHash, Txid};
+use std::collections::HashMap;
+
+/// Number of blocks between difficulty retargets, matching
+/// `blocktree::DIFFICULTY_ADJUSTMENT_INTERVAL`.
+const DIFFICULTY_ADJUSTMENT_INTERVAL: u32 = 2016;
+
+/// Number of blocks of work that must accumulate on top of a block before it
+/// is considered stable, i.e. unlikely enough to be reorged away that it can
+/// be pruned from `unstable_blocks` and folded into the UTXO set.
+pub(crate) const STABILITY_THRESHOLD: u32 = 6;
+
+/// The confirmation status of a transaction the canister has seen.
+#[derive(Debug, PartialEq, Eq)]
+pub struct TransactionStatus {
+    pub block_hash: BlockHash,
+    pub confirmations: u32,
+}
+
+/// Inserts `block` into the store of unstable blocks.
+///
+/// The block is rejected if it does not extend a block already in the
+/// store, or if its header fails proof-of-work or difficulty-retarget
+/// validation (see `blocktree::validate_header`).
+pub fn insert_block(state: &mut State, block: Block) -> Result<(), BlockInsertionError> {
+    let block_hash = block.block_hash();
+
+    // The adapter may resend a hash we've already linked into the tree
+    // header-only (see `heartbeat::record_header_filters`/
+    // `pending_body_fetch`/`bodies_wanted`), this time with its full body.
+    // Its header -- and therefore its hash, height, and validity -- is
+    // unchanged, so update that node in place rather than going through the
+    // logic below, which would append a second, sibling node for the same
+    // hash: a later prune could then drop the tx-index/filter entries for
+    // this hash out from under whichever duplicate "won".
+    if let Some((_, chain)) = state.unstable_blocks.find_mut(&block_hash) {
+        let height = state.unstable_blocks_anchor_height + chain.len() as u32 - 1;
+        state
+            .tx_index
+            .insert_block(block_hash, height, &block.txdata);
+        if !block.txdata.is_empty() {
+            let filter = filters::build_filter(&block_hash, &filter_scripts(state, &block));
+            state.filters.insert(block_hash, filter);
+        }
+        let (existing, _) = state
+            .unstable_blocks
+            .find_mut(&block_hash)
+            .expect("just found above");
+        existing.root = block;
+        return Ok(());
+    }
+
+    let prev_hash = block.header.prev_blockhash;
+
+    let (parent_height, parent_header) = {
+        let anchor_height = state.unstable_blocks_anchor_height;
+        if state.unstable_blocks.root.block_hash() == prev_hash {
+            (anchor_height, state.unstable_blocks.root.header)
+        } else {
+            match state.unstable_blocks.find_mut(&prev_hash) {
+                Some((_, chain)) => (anchor_height + chain.len() as u32 - 1, chain.last().unwrap().header),
+                None => return Err(BlockInsertionError::DoesNotExtendTree(prev_hash)),
+            }
+        }
+    };
+    let height = parent_height + 1;
+
+    // `headers_by_height` only ever retains retarget-boundary headers (see
+    // below), so a retarget height's start is looked up at that same
+    // granularity. It may legitimately be missing, e.g. while validating the
+    // first retarget since the canister started recording headers;
+    // `validate_header` degrades gracefully in that case rather than
+    // rejecting the block outright.
+    let retarget_start = if height % DIFFICULTY_ADJUSTMENT_INTERVAL == 0 {
+        state
+            .headers_by_height
+            .get(&(height - DIFFICULTY_ADJUSTMENT_INTERVAL))
+            .copied()
+    } else {
+        None
+    };
+
+    validate_header(
+        state.utxos.network,
+        height,
+        &parent_header,
+        retarget_start.as_ref(),
+        &block.header,
+    )
+    .map_err(|e| BlockInsertionError::InvalidHeader(block_hash, e))?;
+
+    // Only retarget-boundary headers are ever looked up (as a future
+    // retarget's `retarget_start`), so that's all that needs to be kept.
+    if height % DIFFICULTY_ADJUSTMENT_INTERVAL == 0 {
+        state.headers_by_height.insert(height, block.header);
+    }
+    state
+        .tx_index
+        .insert_block(block_hash, height, &block.txdata);
+    // A header-only block (`block.txdata` empty) carries no scripts to
+    // build a filter from; any filter for it was already supplied by the
+    // adapter alongside its header (see `heartbeat::record_header_filters`)
+    // and must not be overwritten with a degenerate empty one here.
+    if !block.txdata.is_empty() {
+        state.filters.insert(
+            block_hash,
+            filters::build_filter(&block_hash, &filter_scripts(state, &block)),
+        );
+    }
+
+    if state.unstable_blocks.root.block_hash() == prev_hash {
+        state.unstable_blocks.children.push(BlockTree::new(block));
+    } else {
+        let (parent, _) = state
+            .unstable_blocks
+            .find_mut(&prev_hash)
+            .expect("parent was just found above");
+        parent.children.push(BlockTree::new(block));
+    }
+
+    prune_stable_blocks(state);
+
+    Ok(())
+}
+
+/// Advances the anchor of `unstable_blocks` one block at a time while the
+/// tip is more than `STABILITY_THRESHOLD` blocks above it, folding each
+/// passed anchor's transactions into the stable portion of the transaction
+/// index and dropping the entries of any sibling branch that lost the fork
+/// race.
+fn prune_stable_blocks(state: &mut State) {
+    loop {
+        let (_, tip_depth, _) = state.unstable_blocks.tip();
+        if tip_depth <= STABILITY_THRESHOLD || state.unstable_blocks.children.is_empty() {
+            return;
+        }
+
+        // The winning branch is the one with the most accumulated work, not
+        // necessarily the deepest: a harder, shorter fork can outweigh an
+        // easier, longer one. This must track `BlockTree::main_chain`'s
+        // selection, or a branch `main_chain` considers won could be pruned
+        // away here as a loser.
+        let main_child_index = state
+            .unstable_blocks
+            .children
+            .iter()
+            .enumerate()
+            .max_by_key(|(_, child)| child.tip().2 + block_work(child.root.header.bits))
+            .map(|(i, _)| i)
+            .expect("checked above that there is at least one child");
+
+        let mut children = std::mem::take(&mut state.unstable_blocks.children);
+        let new_root = children.swap_remove(main_child_index);
+
+        // Every other branch just lost the race for good: its blocks can
+        // never be confirmed, so drop them from the transaction index.
+        for pruned in children {
+            for block in pruned.blocks() {
+                let hash = block.block_hash();
+                state.tx_index.remove_block(&hash);
+                state.filters.remove(&hash);
+            }
+        }
+
+        state.tx_index.stabilize(
+            state.unstable_blocks.root.block_hash(),
+            state.unstable_blocks_anchor_height,
+        );
+        state.unstable_blocks_anchor_height += 1;
+        state.unstable_blocks = new_root;
+    }
+}
+
+/// Returns the confirmation status of `txid`, if the canister has seen it
+/// confirmed in a block that's still on the main chain. A transaction mined
+/// only in a losing fork is reported as unseen (`None`), even while that
+/// fork is still in `unstable_blocks` awaiting pruning: it's not a
+/// confirmation until it's mined on the chain that's winning. A `Stable`
+/// location is always on the main chain by construction (only the anchor
+/// being advanced past, an ancestor of every block left in the tree, is
+/// ever stabilized) and is no longer in
+/// `unstable_blocks` to check against, so it's trusted without the check.
+pub fn get_transaction_status(state: &State, txid: &Txid) -> Option<TransactionStatus> {
+    let location = state.tx_index.location(txid)?;
+    if matches!(location, TxLocation::Unstable { .. }) && !is_on_main_chain(state, &location.block_hash()) {
+        return None;
+    }
+    let confirmations = main_chain_height(state).saturating_sub(location.height()) + 1;
+    Some(TransactionStatus {
+        block_hash: location.block_hash(),
+        confirmations,
+    })
+}
+
+/// Whether `block_hash` is one of the blocks on the chain with the most
+/// accumulated work.
+fn is_on_main_chain(state: &State, block_hash: &BlockHash) -> bool {
+    state
+        .unstable_blocks
+        .main_chain()
+        .iter()
+        .any(|block| &block.block_hash() == block_hash)
+}
+
+/// Returns all blocks currently in the store of unstable blocks, in no
+/// particular order, with the anchor (the most recent stable block) first.
+pub fn get_unstable_blocks(state: &State) -> Vec<&Block> {
+    fn walk<'a>(tree: &'a BlockTree, out: &mut Vec<&'a Block>) {
+        out.push(&tree.root);
+        for child in &tree.children {
+            walk(child, out);
+        }
+    }
+
+    let mut blocks = vec![];
+    walk(&state.unstable_blocks, &mut blocks);
+    blocks
+}
+
+/// Returns the height of the tip of the chain with the most accumulated
+/// work.
+pub fn main_chain_height(state: &State) -> u32 {
+    let (_, depth, _) = state.unstable_blocks.tip();
+    state.unstable_blocks_anchor_height + depth
+}
+
+/// Returns the scriptPubKeys a BIP158 filter for `block` must commit to:
+/// every non-`OP_RETURN` output it creates, plus every prevout it spends
+/// that is found in the UTXO set or among this block's own outputs. A
+/// prevout found in neither (e.g. one created in another block still in
+/// `unstable_blocks`) is left out of the filter.
+fn filter_scripts(state: &State, block: &Block) -> Vec<Vec<u8>> {
+    let mut in_block_outputs: HashMap<bitcoin::OutPoint, &bitcoin::Script> = HashMap::new();
+    let mut scripts = vec![];
+
+    for tx in &block.txdata {
+        let txid = tx.txid();
+        for (vout, out) in tx.output.iter().enumerate() {
+            if !out.script_pubkey.is_op_return() {
+                scripts.push(out.script_pubkey.to_bytes());
+            }
+            in_block_outputs.insert(
+                bitcoin::OutPoint {
+                    txid,
+                    vout: vout as u32,
+                },
+                &out.script_pubkey,
+            );
+        }
+    }
+
+    for tx in &block.txdata {
+        for input in &tx.input {
+            if let Some(script) = in_block_outputs.get(&input.previous_output) {
+                scripts.push(script.to_bytes());
+            } else if let Some(out) = state.utxos.utxos.get(&input.previous_output) {
+                scripts.push(out.script_pubkey.to_bytes());
+            }
+        }
+    }
+
+    scripts
+}
+
+/// A proof that a transaction is included in a block, as an authentication
+/// path from its position up to the block's `merkle_root`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct TxMerkleProof {
+    pub block_hash: BlockHash,
+    pub position: u32,
+    pub path: Vec<TxMerkleNode>,
+}
+
+/// Builds a Merkle inclusion proof for `txid`, if the canister has it
+/// indexed, it's confirmed on the main chain (i.e. not just mined on a
+/// losing fork still awaiting pruning), and the canister still holds the
+/// confirming block's body (i.e. the block hasn't yet been pruned from
+/// `unstable_blocks`).
+pub fn get_tx_merkle_proof(state: &State, txid: &Txid) -> Option<TxMerkleProof> {
+    let location = state.tx_index.location(txid)?;
+    let (block_hash, position) = match location {
+        TxLocation::Unstable {
+            block_hash, index, ..
+        } => (*block_hash, *index),
+        TxLocation::Stable { .. } => return None,
+    };
+    let block = state
+        .unstable_blocks
+        .main_chain()
+        .into_iter()
+        .find(|block| block.block_hash() == block_hash)?;
+    let leaves: Vec<TxMerkleNode> = block
+        .txdata
+        .iter()
+        .map(|tx| TxMerkleNode::from_hash(tx.txid().as_hash()))
+        .collect();
+
+    Some(TxMerkleProof {
+        block_hash,
+        position,
+        path: merkle_path(leaves, position as usize),
+    })
+}
+
+/// Computes the authentication path from the leaf at `index` up to the
+/// Merkle root, duplicating the last node of any level with an odd number
+/// of nodes (Bitcoin's odd-node rule).
+fn merkle_path(mut level: Vec<TxMerkleNode>, mut index: usize) -> Vec<TxMerkleNode> {
+    let mut path = vec![];
+    while level.len() > 1 {
+        if level.len() % 2 == 1 {
+            level.push(*level.last().unwrap());
+        }
+        let sibling = if index % 2 == 0 { index + 1 } else { index - 1 };
+        path.push(level[sibling]);
+
+        level = level
+            .chunks(2)
+            .map(|pair| merkle_parent(pair[0], pair[1]))
+            .collect();
+        index /= 2;
+    }
+    path
+}
+
+/// Combines two sibling nodes into their parent, per Bitcoin's Merkle tree
+/// construction: `SHA256d(left || right)`.
+fn merkle_parent(left: TxMerkleNode, right: TxMerkleNode) -> TxMerkleNode {
+    let mut bytes = Vec::with_capacity(64);
+    bytes.extend_from_slice(&left.as_hash().into_inner());
+    bytes.extend_from_slice(&right.as_hash().into_inner());
+    TxMerkleNode::hash(&bytes)
+}
+
+/// Returns the BIP158 compact filter for `block_hash`, if the canister has
+/// seen that block.
+pub fn get_block_filter(state: &State, block_hash: &BlockHash) -> Option<&Vec<u8>> {
+    state.filters.get(block_hash)
+}
+
+/// Tests whether `script` might appear in the block with the given hash.
+/// False positives are possible (at the BIP158 basic-filter rate). A script
+/// the block's filter doesn't commit to (see `filter_scripts`), or any
+/// script of a block with no stored filter, is reported absent.
+pub fn script_might_be_in_block(state: &State, block_hash: &BlockHash, script: &[u8]) -> bool {
+    match state.filters.get(block_hash) {
+        Some(filter) => filters::matches(filter, block_hash, script),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaf(byte: u8) -> TxMerkleNode {
+        TxMerkleNode::hash(&[byte; 32])
+    }
+
+    #[test]
+    fn merkle_path_for_single_leaf_is_empty() {
+        assert_eq!(merkle_path(vec![leaf(0)], 0), vec![]);
+    }
+
+    #[test]
+    fn merkle_path_duplicates_the_last_node_of_an_odd_level() {
+        let leaves = vec![leaf(0), leaf(1), leaf(2)];
+        // The 3rd leaf is paired with itself, so its sibling is itself.
+        let path = merkle_path(leaves.clone(), 2);
+        assert_eq!(path[0], leaves[2]);
+    }
+
+    #[test]
+    fn merkle_path_verifies_up_to_the_root() {
+        let leaves = vec![leaf(0), leaf(1), leaf(2), leaf(3)];
+        let root = merkle_parent(
+            merkle_parent(leaves[0], leaves[1]),
+            merkle_parent(leaves[2], leaves[3]),
+        );
+
+        let path = merkle_path(leaves.clone(), 2);
+        // Position 2 is a left node at level 0 (even index) and a right
+        // node at level 1 (index 2 / 2 = 1, odd).
+        let computed = merkle_parent(leaves[2], path[0]);
+        let computed = merkle_parent(path[1], computed);
+        assert_eq!(computed, root);
+    }
+}
+
@@ -0,0 +1,155 @@
+//! An index from transaction id to the block that confirms it, maintained
+//! alongside the store of unstable blocks so that callers can answer "has
+//! this transaction been mined, and how many confirmations does it have?"
+//! without rescanning the chain.
+
+use bitcoin::{BlockHash, Transaction, Txid};
+use std::collections::HashMap;
+
+/// Where a transaction was last seen confirmed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TxLocation {
+    /// The transaction is in a block that may still be reorged away.
+    /// `index` is its position within the block, kept around so a Merkle
+    /// proof can be produced while the block could still move.
+    Unstable {
+        block_hash: BlockHash,
+        height: u32,
+        index: u32,
+    },
+    /// The transaction is in a block deep enough to be treated as
+    /// permanent; its in-block position is no longer retained.
+    Stable { block_hash: BlockHash, height: u32 },
+}
+
+impl TxLocation {
+    pub fn block_hash(&self) -> BlockHash {
+        match self {
+            TxLocation::Unstable { block_hash, .. } | TxLocation::Stable { block_hash, .. } => {
+                *block_hash
+            }
+        }
+    }
+
+    pub fn height(&self) -> u32 {
+        match self {
+            TxLocation::Unstable { height, .. } | TxLocation::Stable { height, .. } => *height,
+        }
+    }
+}
+
+/// Maps transaction ids to the block that confirms them.
+///
+/// The index is reorg-aware: `remove_block` drops the entries of a block
+/// that loses a fork race, and `stabilize` collapses the entries of a block
+/// that can no longer be reorged away.
+#[derive(Default)]
+pub struct TxIndex {
+    locations: HashMap<Txid, TxLocation>,
+    /// Reverse index so a block's entries can be found without scanning
+    /// `locations`.
+    by_block: HashMap<BlockHash, Vec<Txid>>,
+}
+
+impl TxIndex {
+    /// Records the confirming block for every transaction in `txdata`.
+    pub fn insert_block(&mut self, block_hash: BlockHash, height: u32, txdata: &[Transaction]) {
+        let txids = self.by_block.entry(block_hash).or_default();
+        for (index, tx) in txdata.iter().enumerate() {
+            let txid = tx.txid();
+            self.locations.insert(
+                txid,
+                TxLocation::Unstable {
+                    block_hash,
+                    height,
+                    index: index as u32,
+                },
+            );
+            txids.push(txid);
+        }
+    }
+
+    /// Removes all entries for a block that fell out of the unstable set
+    /// because a competing fork won.
+    pub fn remove_block(&mut self, block_hash: &BlockHash) {
+        if let Some(txids) = self.by_block.remove(block_hash) {
+            for txid in txids {
+                // A later block may have re-indexed the same txid; that
+                // entry isn't this block's to drop.
+                if self.locations.get(&txid).map(|l| l.block_hash()) == Some(*block_hash) {
+                    self.locations.remove(&txid);
+                }
+            }
+        }
+    }
+
+    /// Collapses every entry of a now-stable block down to just its block
+    /// hash and height.
+    pub fn stabilize(&mut self, block_hash: BlockHash, height: u32) {
+        if let Some(txids) = self.by_block.get(&block_hash) {
+            for txid in txids {
+                self.locations
+                    .insert(*txid, TxLocation::Stable { block_hash, height });
+            }
+        }
+    }
+
+    /// Looks up the confirming block and in-block position of a transaction,
+    /// if it has been seen.
+    pub fn location(&self, txid: &Txid) -> Option<&TxLocation> {
+        self.locations.get(txid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::{OutPoint, Script, TxIn, TxOut};
+
+    fn dummy_tx(n: u32) -> Transaction {
+        Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: Script::new(),
+                sequence: n,
+                witness: vec![],
+            }],
+            output: vec![TxOut {
+                value: 0,
+                script_pubkey: Script::new(),
+            }],
+        }
+    }
+
+    #[test]
+    fn remove_block_drops_all_of_its_entries() {
+        let mut index = TxIndex::default();
+        let block_hash = BlockHash::default();
+        let txdata = vec![dummy_tx(0), dummy_tx(1)];
+        index.insert_block(block_hash, 10, &txdata);
+
+        assert!(index.location(&txdata[0].txid()).is_some());
+        index.remove_block(&block_hash);
+        assert!(index.location(&txdata[0].txid()).is_none());
+        assert!(index.location(&txdata[1].txid()).is_none());
+    }
+
+    #[test]
+    fn stabilize_collapses_the_in_block_index() {
+        let mut index = TxIndex::default();
+        let block_hash = BlockHash::default();
+        let txdata = vec![dummy_tx(0)];
+        index.insert_block(block_hash, 10, &txdata);
+        index.stabilize(block_hash, 10);
+
+        assert_eq!(
+            *index.location(&txdata[0].txid()).unwrap(),
+            TxLocation::Stable {
+                block_hash,
+                height: 10
+            }
+        );
+    }
+}